@@ -0,0 +1,166 @@
+/// downloader.rs
+///
+/// Provides a small `reqwest`-backed subsystem for fetching remote files
+/// (packages, manifests) over HTTP, with a shared client and a progress
+/// snapshot the REST layer can poll.
+
+use reqwest;
+
+use std::fmt;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Maximum number of redirects a single download is allowed to follow
+/// before it's treated as an error, rather than looping forever.
+const MAX_REDIRECTS : usize = 10;
+
+/// Describes why a download failed.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The HTTP client itself failed (DNS, TLS, too many redirects, ...).
+    Request(reqwest::Error),
+    /// The server responded with a non-success status code.
+    BadStatus(reqwest::StatusCode),
+    /// Reading the response body failed partway through.
+    Io(::std::io::Error)
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DownloadError::Request(ref err) => write!(f, "request failed: {}", err),
+            DownloadError::BadStatus(ref status) => write!(f, "server returned {}", status),
+            DownloadError::Io(ref err) => write!(f, "failed to read response body: {}", err)
+        }
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(err : reqwest::Error) -> Self {
+        DownloadError::Request(err)
+    }
+}
+
+/// A snapshot of an in-progress (or completed) download, safe to serialize
+/// and hand back to the frontend as-is.
+#[derive(Clone, Serialize)]
+pub struct DownloadProgress {
+    pub url : Option<String>,
+    pub bytes_received : u64,
+    pub total_bytes : Option<u64>,
+    pub complete : bool,
+    pub error : Option<String>
+}
+
+impl DownloadProgress {
+    fn idle() -> Self {
+        DownloadProgress {
+            url : None,
+            bytes_received : 0,
+            total_bytes : None,
+            complete : false,
+            error : None
+        }
+    }
+}
+
+/// Wraps a single shared `reqwest::Client` so the web server thread isn't
+/// constructing a fresh client (and re-negotiating proxy/TLS settings) on
+/// every request.
+pub struct Downloader {
+    client : reqwest::Client,
+    progress : Mutex<DownloadProgress>
+}
+
+impl Downloader {
+    /// Creates a downloader with a bounded-redirect client.
+    pub fn new() -> Self {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::RedirectPolicy::limited(MAX_REDIRECTS))
+            .build()
+            .expect("failed to build the shared HTTP client");
+
+        Downloader {
+            client,
+            progress : Mutex::new(DownloadProgress::idle())
+        }
+    }
+
+    /// Returns a snapshot of the current (or most recently finished)
+    /// download, for the `/api/download-progress` endpoint.
+    pub fn progress(&self) -> DownloadProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Starts a download on its own worker thread and returns immediately,
+    /// so the hyper reactor thread handling the triggering request isn't
+    /// blocked for the transfer's duration. Progress can be polled
+    /// concurrently through `progress()` while the thread runs.
+    pub fn spawn_download(self : &Arc<Self>, url : String) {
+        let downloader = self.clone();
+
+        thread::spawn(move || {
+            downloader.download(&url);
+        });
+    }
+
+    /// Fetches `url` in a single pass, following redirects up to
+    /// `MAX_REDIRECTS`, and returns the full response body. Progress is
+    /// streamed into the shared snapshot as bytes arrive so a concurrent
+    /// caller can poll `progress()` for a status bar.
+    pub fn download(&self, url : &str) -> Result<Vec<u8>, DownloadError> {
+        {
+            let mut progress = self.progress.lock().unwrap();
+            *progress = DownloadProgress {
+                url : Some(url.to_owned()),
+                bytes_received : 0,
+                total_bytes : None,
+                complete : false,
+                error : None
+            };
+        }
+
+        let result = self.download_inner(url);
+
+        let mut progress = self.progress.lock().unwrap();
+        match result {
+            Ok(ref bytes) => {
+                progress.bytes_received = bytes.len() as u64;
+                progress.complete = true;
+            },
+            Err(ref err) => {
+                progress.error = Some(err.to_string());
+                progress.complete = true;
+            }
+        }
+
+        result
+    }
+
+    fn download_inner(&self, url : &str) -> Result<Vec<u8>, DownloadError> {
+        let mut response = self.client.get(url).send()?;
+
+        if !response.status().is_success() {
+            return Err(DownloadError::BadStatus(response.status()));
+        }
+
+        let total_bytes = response.content_length();
+        self.progress.lock().unwrap().total_bytes = total_bytes;
+
+        let mut body = Vec::new();
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            let read = response.read(&mut buffer).map_err(DownloadError::Io)?;
+            if read == 0 {
+                break;
+            }
+
+            body.extend_from_slice(&buffer[..read]);
+            self.progress.lock().unwrap().bytes_received = body.len() as u64;
+        }
+
+        Ok(body)
+    }
+}
@@ -0,0 +1,56 @@
+/// events.rs
+///
+/// A bounded in-memory feed of recent HTTP requests handled by the REST
+/// server, so support builds can inspect the installer's HTTP activity
+/// without attaching an external debugger.
+
+use log::debug;
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Maximum number of events retained before the oldest are evicted.
+const MAX_EVENTS : usize = 200;
+
+/// A single logged request/response pair.
+#[derive(Clone, Serialize)]
+pub struct RequestEvent {
+    pub method : String,
+    pub path : String,
+    pub status : u16,
+    pub bytes : u64,
+    pub duration_ms : u64
+}
+
+/// A bounded ring buffer of the most recently handled requests.
+pub struct EventLog {
+    events : Mutex<VecDeque<RequestEvent>>
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        EventLog {
+            events : Mutex::new(VecDeque::with_capacity(MAX_EVENTS))
+        }
+    }
+
+    /// Records an event, evicting the oldest entry once the log is full,
+    /// and emits it through the `log` facade rather than stdout, so it's
+    /// only noisy when a build actually turns on `debug` logging.
+    pub fn record(&self, event : RequestEvent) {
+        debug!("{} {} -> {} ({} bytes, {}ms)",
+               event.method, event.path, event.status, event.bytes, event.duration_ms);
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+
+        events.push_back(event);
+    }
+
+    /// Returns the retained events, oldest first.
+    pub fn recent(&self) -> Vec<RequestEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -8,22 +8,41 @@ use nfd::Response as NfdResponse;
 
 use serde_json;
 
+use url;
+
 use futures::future;
 use futures::future::FutureResult;
 
 use hyper;
-use hyper::{Get, StatusCode, Error as HyperError};
-use hyper::header::{ContentLength, ContentType};
+use hyper::{Get, Post, StatusCode, Error as HyperError};
+#[cfg(unix)]
+use hyperlocal;
+use hyper::header::{AcceptRanges, ByteRangeSpec, CacheControl, CacheDirective, ContentLength,
+                    ContentRange, ContentRangeSpec, ContentType, ETag, EntityTag, IfNoneMatch,
+                    Range, RangeUnit};
+use hyper::mime::{self, Mime};
 use hyper::server::{Http, Service, Request, Response};
 
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::thread::{self, JoinHandle};
 use std::process::exit;
 use std::sync::Arc;
 use std::sync::mpsc::channel;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
 
 use assets;
 
+use downloader::Downloader;
+
+use events::{EventLog, RequestEvent};
+
 use installer::InstallerFramework;
 
 #[derive(Serialize)]
@@ -31,15 +50,42 @@ struct FileSelection {
     path : Option<String>
 }
 
+/// Identifies where a `WebServer` can be reached, since it may be bound to
+/// either a TCP port or (on supporting platforms) a Unix domain socket.
+#[derive(Clone)]
+pub enum ServerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+impl ServerAddr {
+    /// Returns the bound `SocketAddr`, if this server is listening on TCP.
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            ServerAddr::Tcp(addr) => Some(addr),
+            ServerAddr::Unix(_) => None
+        }
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            ServerAddr::Unix(ref path) => write!(f, "{}", path.display())
+        }
+    }
+}
+
 /// Encapsulates Hyper's state.
 pub struct WebServer {
     handle : JoinHandle<()>,
-    addr : SocketAddr
+    addr : ServerAddr
 }
 
 impl WebServer {
     /// Returns the bound address that the server is running from.
-    pub fn get_addr(&self) -> SocketAddr {
+    pub fn get_addr(&self) -> ServerAddr {
         self.addr.clone()
     }
 
@@ -56,11 +102,15 @@ impl WebServer {
 
         let handle = thread::spawn(move || {
             let shared_framework = Arc::new(framework);
+            let shared_downloader = Arc::new(Downloader::new());
+            let shared_events = Arc::new(EventLog::new());
 
             let server =
                 Http::new().bind(&addr, move ||
                     Ok(WebService {
-                        framework : shared_framework.clone()
+                        framework : shared_framework.clone(),
+                        downloader : shared_downloader.clone(),
+                        events : shared_events.clone()
                     })
                 ).unwrap();
 
@@ -72,13 +122,60 @@ impl WebServer {
         let addr = receiver.recv().unwrap();
 
         Ok(WebServer {
-            handle, addr
+            handle, addr : ServerAddr::Tcp(addr)
+        })
+    }
+
+    /// Creates a new web server bound to a Unix domain socket at `path`,
+    /// rather than a TCP port, so access is governed by filesystem
+    /// permissions instead of a guessable loopback port.
+    #[cfg(unix)]
+    pub fn with_unix_socket<P : AsRef<Path>>(framework : InstallerFramework, path : P)
+        -> Result<Self, HyperError> {
+        let path = path.as_ref().to_path_buf();
+
+        // A stale socket left over from a previous run (crash, unclean
+        // shutdown, ...) would otherwise make the bind below fail.
+        match fs::remove_file(&path) {
+            Ok(_) => {},
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(HyperError::from(err))
+        }
+
+        let (sender, receiver) = channel();
+        let bound_path = path.clone();
+
+        let handle = thread::spawn(move || {
+            let shared_framework = Arc::new(framework);
+            let shared_downloader = Arc::new(Downloader::new());
+            let shared_events = Arc::new(EventLog::new());
+
+            let server =
+                hyperlocal::server::Http::new().bind(&path, move ||
+                    Ok(WebService {
+                        framework : shared_framework.clone(),
+                        downloader : shared_downloader.clone(),
+                        events : shared_events.clone()
+                    })
+                ).unwrap();
+
+            sender.send(()).unwrap();
+
+            server.run().unwrap();
+        });
+
+        receiver.recv().unwrap();
+
+        Ok(WebServer {
+            handle, addr : ServerAddr::Unix(bound_path)
         })
     }
 }
 
 struct WebService {
-    framework : Arc<InstallerFramework>
+    framework : Arc<InstallerFramework>,
+    downloader : Arc<Downloader>,
+    events : Arc<EventLog>
 }
 
 impl Service for WebService {
@@ -88,7 +185,11 @@ impl Service for WebService {
     type Future =  FutureResult<Self::Response, Self::Error>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        future::ok(match (req.method(), req.path()) {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let path = req.path().to_owned();
+
+        let response = match (req.method(), req.path()) {
             // This endpoint should be usable directly from a <script> tag during loading.
             // TODO: Handle errors
             (&Get, "/api/config") => {
@@ -101,7 +202,18 @@ impl Service for WebService {
                     .with_body(file)
             },
             (&Get, "/api/file-select") => {
-                let file_dialog = nfd::open_pick_folder(None).unwrap();
+                let params = parse_query(req.query());
+
+                let mode = params.get("mode").map(String::as_str).unwrap_or("folder");
+                let filter = params.get("filter").map(String::as_str);
+                let default = params.get("default").map(String::as_str);
+
+                let file_dialog = match mode {
+                    "file" => nfd::open_file_dialog(filter, default),
+                    "save" => nfd::open_save_dialog(filter, default),
+                    _ => nfd::open_pick_folder(default)
+                }.unwrap();
+
                 let file = match file_dialog {
                     NfdResponse::Okay(path) => Some(path),
                     _ => None
@@ -132,6 +244,42 @@ impl Service for WebService {
                     .with_header(ContentType::json())
                     .with_body(file)
             },
+            (&Post, "/api/download") => {
+                let params = parse_query(req.query());
+
+                match params.get("url") {
+                    Some(url) => {
+                        // Runs on its own thread so this hyper worker isn't blocked for the
+                        // transfer's duration; /api/download-progress can be polled while it runs.
+                        self.downloader.spawn_download(url.clone());
+
+                        let file = serde_json::to_string(&self.downloader.progress()).unwrap();
+
+                        Response::<hyper::Body>::new()
+                            .with_status(StatusCode::Accepted)
+                            .with_header(ContentLength(file.len() as u64))
+                            .with_header(ContentType::json())
+                            .with_body(file)
+                    },
+                    None => Response::new().with_status(StatusCode::BadRequest)
+                }
+            },
+            (&Get, "/api/download-progress") => {
+                let file = serde_json::to_string(&self.downloader.progress()).unwrap();
+
+                Response::<hyper::Body>::new()
+                    .with_header(ContentLength(file.len() as u64))
+                    .with_header(ContentType::json())
+                    .with_body(file)
+            },
+            (&Get, "/api/events") => {
+                let file = serde_json::to_string(&self.events.recent()).unwrap();
+
+                Response::<hyper::Body>::new()
+                    .with_header(ContentLength(file.len() as u64))
+                    .with_header(ContentType::json())
+                    .with_body(file)
+            },
             (&Get, "/api/exit") => {
                 exit(0);
             },
@@ -145,13 +293,53 @@ impl Service for WebService {
                     path += "index.html";
                 }
 
-                println!("Trying {} => {}", req.path(), path);
-
                 match assets::file_from_string(&path) {
-                    Some((content_type, file)) => Response::<hyper::Body>::new()
-                        .with_header(ContentLength(file.len() as u64))
-                        .with_header(content_type)
-                        .with_body(file),
+                    Some((content_type, file)) => {
+                        // `assets::file_from_string`'s returned asset type isn't assumed to be
+                        // `Vec<u8>` here; borrow it as bytes for hashing/slicing instead, so this
+                        // compiles whether the asset is embedded as a `String` or raw bytes.
+                        let bytes : &[u8] = file.as_ref();
+                        let etag = etag_for(bytes);
+
+                        let resolved_type = guess_content_type(&path)
+                            .unwrap_or(content_type);
+
+                        let response = Response::<hyper::Body>::new()
+                            .with_header(resolved_type)
+                            .with_header(CacheControl(cache_directives_for(&path)))
+                            .with_header(ETag(etag.clone()))
+                            .with_header(AcceptRanges(vec![RangeUnit::Bytes]));
+
+                        if etag_matches(req.headers().get::<IfNoneMatch>(), &etag) {
+                            response.with_status(StatusCode::NotModified)
+                        } else {
+                            match req.headers().get::<Range>() {
+                                Some(range) => match serve_range(range, bytes) {
+                                    Some((start, end)) => {
+                                        let slice = bytes[start as usize..(end as usize) + 1].to_vec();
+
+                                        response
+                                            .with_status(StatusCode::PartialContent)
+                                            .with_header(ContentLength(slice.len() as u64))
+                                            .with_header(ContentRange(ContentRangeSpec::Bytes {
+                                                range : Some((start, end)),
+                                                instance_length : Some(bytes.len() as u64)
+                                            }))
+                                            .with_body(slice)
+                                    },
+                                    None => response
+                                        .with_status(StatusCode::RangeNotSatisfiable)
+                                        .with_header(ContentRange(ContentRangeSpec::Bytes {
+                                            range : None,
+                                            instance_length : Some(bytes.len() as u64)
+                                        }))
+                                },
+                                None => response
+                                    .with_header(ContentLength(bytes.len() as u64))
+                                    .with_body(file)
+                            }
+                        }
+                    },
                     None => Response::new()
                         .with_status(StatusCode::NotFound)
                 }
@@ -160,7 +348,17 @@ impl Service for WebService {
             _ => {
                 Response::new().with_status(StatusCode::NotFound)
             }
-        })
+        };
+
+        self.events.record(RequestEvent {
+            method,
+            path,
+            status : response.status().as_u16(),
+            bytes : response.headers().get::<ContentLength>().map(|len| len.0).unwrap_or(0),
+            duration_ms : duration_to_millis(start.elapsed())
+        });
+
+        future::ok(response)
     }
 }
 
@@ -168,3 +366,227 @@ impl Service for WebService {
 fn enscapsulate_json(field_name : &str, json : &str) -> String {
     format!("var {} = {};", field_name, json)
 }
+
+/// Guesses the MIME content type of a path from its extension, returning
+/// `None` if the extension is unknown or missing so callers can fall back
+/// to whatever type the asset was embedded with.
+fn guess_content_type(path : &str) -> Option<ContentType> {
+    let extension = path.rsplit('.').next()?;
+
+    let mime : Mime = match extension.to_lowercase().as_str() {
+        "js" => "application/javascript".parse().ok()?,
+        "css" => mime::TEXT_CSS,
+        "html" | "htm" => mime::TEXT_HTML,
+        "json" => mime::APPLICATION_JSON,
+        "svg" => "image/svg+xml".parse().ok()?,
+        "png" => mime::IMAGE_PNG,
+        "jpg" | "jpeg" => mime::IMAGE_JPEG,
+        "gif" => mime::IMAGE_GIF,
+        "ico" => "image/x-icon".parse().ok()?,
+        "woff" => "font/woff".parse().ok()?,
+        "woff2" => "font/woff2".parse().ok()?,
+        "ttf" => "font/ttf".parse().ok()?,
+        "wasm" => "application/wasm".parse().ok()?,
+        _ => return None
+    };
+
+    Some(ContentType(mime))
+}
+
+/// Picks the `Cache-Control` directives for a served asset. HTML entry
+/// points aren't named with a content hash, so they're served with
+/// `no-cache` and must be revalidated on every load; everything else
+/// (JS/CSS/fonts/images, which the bundler content-hashes) is safe to
+/// cache for a year.
+fn cache_directives_for(path : &str) -> Vec<CacheDirective> {
+    let is_html = path.ends_with(".html") || path.ends_with(".htm");
+
+    if is_html {
+        vec![CacheDirective::NoCache, CacheDirective::MustRevalidate]
+    } else {
+        vec![CacheDirective::Public, CacheDirective::MaxAge(31536000)]
+    }
+}
+
+/// Converts a `Duration` to whole milliseconds, for logging request timings.
+fn duration_to_millis(duration : ::std::time::Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000
+}
+
+/// Decodes a request's raw query string (e.g. `mode=file&filter=exe,msi`)
+/// into a lookup of its key/value pairs, so handlers can pull out options
+/// without each reimplementing percent-decoding.
+fn parse_query(query : Option<&str>) -> HashMap<String, String> {
+    match query {
+        Some(query) => url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+        None => HashMap::new()
+    }
+}
+
+/// Resolves a `Range` header against the length of `file`, returning the
+/// inclusive `(start, end)` byte bounds to serve, or `None` if the range
+/// can't be satisfied (and a `416` should be sent instead).
+///
+/// Only a single byte-range is honoured; multi-range requests fall back to
+/// serving the first range, matching the common case of resumable
+/// downloads and media seeking rather than full multipart/byteranges support.
+fn serve_range(range : &Range, file : &[u8]) -> Option<(u64, u64)> {
+    let total = file.len() as u64;
+    if total == 0 {
+        return None;
+    }
+
+    let spec = match *range {
+        Range::Bytes(ref specs) => specs.first()?,
+        _ => return None
+    };
+
+    let (start, end) = match *spec {
+        ByteRangeSpec::FromTo(start, end) => (start, end.min(total - 1)),
+        ByteRangeSpec::AllFrom(start) => (start, total - 1),
+        ByteRangeSpec::Last(n) => (total.saturating_sub(n), total - 1)
+    };
+
+    if start > end || start >= total {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+/// Computes a strong ETag for a blob of bytes, used to let browsers skip
+/// re-fetching unchanged static assets between wizard steps.
+fn etag_for(bytes : &[u8]) -> EntityTag {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+
+    EntityTag::strong(format!("{:x}", hasher.finish()))
+}
+
+/// Checks a request's `If-None-Match` header against the current ETag, so a
+/// static asset can be answered with `304 Not Modified` instead of the full
+/// body when the client already has an up-to-date copy.
+fn etag_matches(if_none_match : Option<&IfNoneMatch>, etag : &EntityTag) -> bool {
+    match if_none_match {
+        Some(&IfNoneMatch::Any) => true,
+        Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guess_content_type_known_extensions() {
+        assert_eq!(guess_content_type("/app.js"), Some(ContentType("application/javascript".parse().unwrap())));
+        assert_eq!(guess_content_type("/app.css"), Some(ContentType(mime::TEXT_CSS)));
+        assert_eq!(guess_content_type("/logo.svg"), Some(ContentType("image/svg+xml".parse().unwrap())));
+        assert_eq!(guess_content_type("/font.woff2"), Some(ContentType("font/woff2".parse().unwrap())));
+    }
+
+    #[test]
+    fn guess_content_type_unknown_or_missing_extension() {
+        assert_eq!(guess_content_type("/README"), None);
+        assert_eq!(guess_content_type("/data.unknownext"), None);
+    }
+
+    #[test]
+    fn cache_directives_for_html_is_revalidated() {
+        assert_eq!(cache_directives_for("/index.html"),
+                   vec![CacheDirective::NoCache, CacheDirective::MustRevalidate]);
+        assert_eq!(cache_directives_for("/legacy.htm"),
+                   vec![CacheDirective::NoCache, CacheDirective::MustRevalidate]);
+    }
+
+    #[test]
+    fn cache_directives_for_hashed_assets_are_long_cached() {
+        assert_eq!(cache_directives_for("/app.abcd1234.js"),
+                   vec![CacheDirective::Public, CacheDirective::MaxAge(31536000)]);
+    }
+
+    #[test]
+    fn parse_query_decodes_pairs() {
+        let params = parse_query(Some("mode=file&filter=exe%2Cmsi"));
+
+        assert_eq!(params.get("mode"), Some(&"file".to_owned()));
+        assert_eq!(params.get("filter"), Some(&"exe,msi".to_owned()));
+    }
+
+    #[test]
+    fn parse_query_handles_missing_query() {
+        assert!(parse_query(None).is_empty());
+    }
+
+    #[test]
+    fn serve_range_from_to_is_clamped_to_file_length() {
+        let file = [0u8; 10];
+        let range = Range::Bytes(vec![ByteRangeSpec::FromTo(2, 100)]);
+
+        assert_eq!(serve_range(&range, &file), Some((2, 9)));
+    }
+
+    #[test]
+    fn serve_range_all_from() {
+        let file = [0u8; 10];
+        let range = Range::Bytes(vec![ByteRangeSpec::AllFrom(5)]);
+
+        assert_eq!(serve_range(&range, &file), Some((5, 9)));
+    }
+
+    #[test]
+    fn serve_range_suffix_last_n_bytes() {
+        let file = [0u8; 10];
+        let range = Range::Bytes(vec![ByteRangeSpec::Last(3)]);
+
+        assert_eq!(serve_range(&range, &file), Some((7, 9)));
+    }
+
+    #[test]
+    fn serve_range_unsatisfiable_start_past_end() {
+        let file = [0u8; 10];
+        let range = Range::Bytes(vec![ByteRangeSpec::FromTo(20, 30)]);
+
+        assert_eq!(serve_range(&range, &file), None);
+    }
+
+    #[test]
+    fn serve_range_empty_file_is_unsatisfiable() {
+        let file : [u8; 0] = [];
+        let range = Range::Bytes(vec![ByteRangeSpec::AllFrom(0)]);
+
+        assert_eq!(serve_range(&range, &file), None);
+    }
+
+    #[test]
+    fn etag_matches_no_header_is_not_a_match() {
+        let etag = etag_for(b"hello");
+
+        assert!(!etag_matches(None, &etag));
+    }
+
+    #[test]
+    fn etag_matches_any_always_matches() {
+        let etag = etag_for(b"hello");
+
+        assert!(etag_matches(Some(&IfNoneMatch::Any), &etag));
+    }
+
+    #[test]
+    fn etag_matches_same_content_matches() {
+        let etag = etag_for(b"hello");
+
+        assert!(etag_matches(Some(&IfNoneMatch::Items(vec![etag.clone()])), &etag));
+    }
+
+    #[test]
+    fn etag_matches_different_content_does_not_match() {
+        let etag = etag_for(b"hello");
+        let other = etag_for(b"goodbye");
+
+        assert!(!etag_matches(Some(&IfNoneMatch::Items(vec![other])), &etag));
+    }
+}